@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use http::{HeaderValue, Request};
+use hyper::Body;
+
+/// Header clients set to select which application's token the proxy should
+/// inject, so a single deployment can front several Discord apps.
+pub const APPLICATION_ID_HEADER: &str = "X-Application-Id";
+
+/// Produces the `Authorization` header value to send to Discord for an
+/// incoming request. Implementations may inspect the request (e.g. to pick
+/// a token per application) but must not mutate it.
+pub trait Authenticator: Send + Sync {
+    fn authorize(&self, req: &Request<Body>) -> Result<HeaderValue>;
+}
+
+/// Injects a single bot token for every request, the proxy's original,
+/// hardcoded behavior.
+pub struct BotTokenAuth {
+    token: String,
+}
+
+impl BotTokenAuth {
+    pub fn new(token: String) -> Self {
+        Self { token }
+    }
+}
+
+impl Authenticator for BotTokenAuth {
+    fn authorize(&self, _req: &Request<Body>) -> Result<HeaderValue> {
+        Ok(HeaderValue::from_str(&format!("Bot {}", self.token))?)
+    }
+}
+
+/// Injects a single bearer token for every request.
+pub struct BearerAuth {
+    token: String,
+}
+
+impl BearerAuth {
+    pub fn new(token: String) -> Self {
+        Self { token }
+    }
+}
+
+impl Authenticator for BearerAuth {
+    fn authorize(&self, _req: &Request<Body>) -> Result<HeaderValue> {
+        Ok(HeaderValue::from_str(&format!("Bearer {}", self.token))?)
+    }
+}
+
+/// Picks among several per-application bot tokens based on the inbound
+/// `X-Application-Id` header, falling back to a default application when
+/// the header is absent.
+pub struct RoutingAuth {
+    apps: HashMap<String, BotTokenAuth>,
+    default_app_id: String,
+}
+
+impl RoutingAuth {
+    pub fn new(apps: HashMap<String, String>, default_app_id: String) -> Self {
+        Self {
+            apps: apps
+                .into_iter()
+                .map(|(id, token)| (id, BotTokenAuth::new(token)))
+                .collect(),
+            default_app_id,
+        }
+    }
+}
+
+impl Authenticator for RoutingAuth {
+    fn authorize(&self, req: &Request<Body>) -> Result<HeaderValue> {
+        let app_id = req
+            .headers()
+            .get(APPLICATION_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or(&self.default_app_id);
+
+        let auth = self
+            .apps
+            .get(app_id)
+            .ok_or_else(|| anyhow!("unknown application id {}", app_id))?;
+
+        auth.authorize(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_app_id(app_id: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder();
+        if let Some(app_id) = app_id {
+            builder = builder.header(APPLICATION_ID_HEADER, app_id);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn routing_auth() -> RoutingAuth {
+        let mut apps = HashMap::new();
+        apps.insert("default-app".to_string(), "default-token".to_string());
+        apps.insert("other-app".to_string(), "other-token".to_string());
+        RoutingAuth::new(apps, "default-app".to_string())
+    }
+
+    #[test]
+    fn falls_back_to_the_default_app_when_no_header_is_present() {
+        let auth = routing_auth();
+        let header = auth.authorize(&request_with_app_id(None)).unwrap();
+        assert_eq!(header, HeaderValue::from_static("Bot default-token"));
+    }
+
+    #[test]
+    fn routes_by_the_application_id_header() {
+        let auth = routing_auth();
+        let header = auth
+            .authorize(&request_with_app_id(Some("other-app")))
+            .unwrap();
+        assert_eq!(header, HeaderValue::from_static("Bot other-token"));
+    }
+
+    #[test]
+    fn unknown_application_id_is_an_error() {
+        let auth = routing_auth();
+        assert!(auth
+            .authorize(&request_with_app_id(Some("nonexistent")))
+            .is_err());
+    }
+}
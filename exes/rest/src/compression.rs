@@ -0,0 +1,109 @@
+use async_compression::tokio::bufread::{DeflateEncoder, GzipEncoder};
+use async_compression::Level;
+use futures::stream::StreamExt;
+use http::HeaderValue;
+use hyper::Body;
+use serde::Deserialize;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Picks the first of `allowed` that also appears in the client's
+/// `Accept-Encoding` header with a non-zero quality value (`;q=0` explicitly
+/// forbids an encoding per RFC 7231 section 5.3.4).
+pub fn negotiate(accept_encoding: &HeaderValue, allowed: &[Encoding]) -> Option<Encoding> {
+    let accept_encoding = accept_encoding.to_str().ok()?;
+
+    let accepted: Vec<(&str, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let name = parts.next()?;
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((name, quality))
+        })
+        .collect();
+
+    allowed.iter().copied().find(|encoding| {
+        accepted
+            .iter()
+            .any(|(name, quality)| *quality > 0.0 && name.eq_ignore_ascii_case(encoding.as_str()))
+    })
+}
+
+/// Wraps `body` in a streaming encoder for `encoding`.
+pub fn compress(body: Body, encoding: Encoding) -> Body {
+    let reader = StreamReader::new(
+        body.map(|chunk| chunk.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))),
+    );
+
+    match encoding {
+        Encoding::Gzip => {
+            Body::wrap_stream(ReaderStream::new(GzipEncoder::with_quality(reader, Level::Default)))
+        }
+        Encoding::Deflate => Body::wrap_stream(ReaderStream::new(DeflateEncoder::with_quality(
+            reader,
+            Level::Default,
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALLOWED: [Encoding; 2] = [Encoding::Gzip, Encoding::Deflate];
+
+    #[test]
+    fn prefers_the_first_allowed_encoding_present() {
+        let header = HeaderValue::from_static("deflate, gzip");
+        assert_eq!(negotiate(&header, &ALLOWED), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn falls_back_to_the_next_allowed_encoding() {
+        let header = HeaderValue::from_static("deflate");
+        assert_eq!(negotiate(&header, &ALLOWED), Some(Encoding::Deflate));
+    }
+
+    #[test]
+    fn no_overlap_returns_none() {
+        let header = HeaderValue::from_static("br");
+        assert_eq!(negotiate(&header, &ALLOWED), None);
+    }
+
+    #[test]
+    fn q_zero_excludes_that_encoding() {
+        let header = HeaderValue::from_static("gzip;q=0, deflate");
+        assert_eq!(negotiate(&header, &ALLOWED), Some(Encoding::Deflate));
+    }
+
+    #[test]
+    fn q_zero_on_every_entry_returns_none() {
+        let header = HeaderValue::from_static("gzip;q=0, deflate;q=0");
+        assert_eq!(negotiate(&header, &ALLOWED), None);
+    }
+
+    #[test]
+    fn a_positive_quality_still_matches() {
+        let header = HeaderValue::from_static("gzip;q=0.5");
+        assert_eq!(negotiate(&header, &ALLOWED), Some(Encoding::Gzip));
+    }
+}
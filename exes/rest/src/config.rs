@@ -0,0 +1,34 @@
+use serde::Deserialize;
+
+use crate::compression::Encoding;
+
+/// Configuration for the reverse proxy and its connection to the distributed ratelimiter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReverseProxyConfig {
+    pub ratelimiter_address: String,
+    pub ratelimiter_port: u16,
+    /// Number of replica nodes (beyond the primary) consulted for each ticket
+    /// or headers submission, so a single unreachable node does not stall
+    /// requests routed to it.
+    #[serde(default = "default_replication_factor")]
+    pub replication_factor: usize,
+    /// Encodings offered to clients, in preference order, when negotiating
+    /// response compression.
+    #[serde(default = "default_compression_algorithms")]
+    pub compression_algorithms: Vec<Encoding>,
+    /// Minimum upstream body size, in bytes, before a response is compressed.
+    #[serde(default = "default_compression_min_size")]
+    pub compression_min_size: usize,
+}
+
+fn default_replication_factor() -> usize {
+    2
+}
+
+fn default_compression_algorithms() -> Vec<Encoding> {
+    vec![Encoding::Gzip, Encoding::Deflate]
+}
+
+fn default_compression_min_size() -> usize {
+    1024
+}
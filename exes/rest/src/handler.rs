@@ -3,21 +3,30 @@ use std::{
     convert::TryFrom,
     hash::{Hash, Hasher},
     str::FromStr,
+    sync::Arc,
     time::Instant,
 };
 
 use anyhow::bail;
 use http::{
-    header::{AUTHORIZATION, CONNECTION, HOST, TRANSFER_ENCODING, UPGRADE},
-    HeaderValue, Method as HttpMethod, Request, Response, Uri,
+    header::{ACCEPT_ENCODING, AUTHORIZATION, CONNECTION, CONTENT_ENCODING, CONTENT_LENGTH, HOST,
+        TRANSFER_ENCODING, UPGRADE, VARY},
+    HeaderValue, Method as HttpMethod, Request, Response, StatusCode, Uri,
 };
 use hyper::{client::HttpConnector, Body, Client};
 use hyper_tls::HttpsConnector;
 use shared::log::error;
 use twilight_http_ratelimiting::{Method, Path};
 
+use crate::auth::{Authenticator, APPLICATION_ID_HEADER};
+use crate::compression::{compress, negotiate};
+use crate::local_limits::LocalLimitCache;
 use crate::ratelimit_client::RemoteRatelimiter;
 
+/// Longest wait the proxy will absorb itself before a request that is
+/// guaranteed to 429; anything longer is rejected immediately instead.
+const MAX_LOCAL_SLEEP: std::time::Duration = std::time::Duration::from_secs(2);
+
 /// Normalizes the path
 fn normalize_path(request_path: &str) -> (&str, &str) {
     if let Some(trimmed_path) = request_path.strip_prefix("/api") {
@@ -39,7 +48,8 @@ fn normalize_path(request_path: &str) -> (&str, &str) {
 pub async fn handle_request(
     client: Client<HttpsConnector<HttpConnector>, Body>,
     ratelimiter: RemoteRatelimiter,
-    token: &str,
+    local_limits: LocalLimitCache,
+    authenticator: Arc<dyn Authenticator>,
     mut request: Request<Body>,
 ) -> Result<Response<Body>, anyhow::Error> {
     let (hash, uri_string) = {
@@ -80,21 +90,61 @@ pub async fn handle_request(
         (hash.finish().to_string(), uri_string)
     };
 
-    let start_ticket_request = Instant::now();
-    let header_sender = match ratelimiter.ticket(hash).await {
-        Ok(sender) => sender,
-        Err(e) => {
-            error!("Failed to receive ticket for ratelimiting: {:?}", e);
-            bail!("failed to reteive ticket");
-        }
+    // Isolate ratelimit accounting per application, so one proxy deployment
+    // can front several Discord apps without their buckets colliding.
+    let app_id = request
+        .headers()
+        .get(APPLICATION_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let hash = match &app_id {
+        Some(app_id) => format!("{}:{}", app_id, hash),
+        None => hash,
     };
+    let app_id = app_id.unwrap_or_default();
+
+    // Reject requests for an unresolvable application id before anything
+    // else touches the ring or the local cache: both are keyed by app id,
+    // and a ticket spent or a cache entry seeded for a bogus id is a
+    // resource an attacker can burn for free.
+    let auth_header = authenticator.authorize(&request)?;
+
+    // Captured before the request is sent upstream, since the client's
+    // Accept-Encoding negotiates compression of the response we send back,
+    // not of what we request from Discord.
+    let accept_encoding = request.headers().get(ACCEPT_ENCODING).cloned();
+    // Also captured before the move into the upstream request: a HEAD
+    // response carries the headers of the GET it describes but never a
+    // body, so it must never gain a Content-Encoding.
+    let is_head = *request.method() == HttpMethod::HEAD;
+
+    // Routes that share a Discord bucket should land on the same node even
+    // if their paths hash differently, so resolve the bucket-aware key
+    // before asking for a ticket.
+    let mut key = ratelimiter.resolve_key(&hash).await;
+
+    // Avoid a round trip that is guaranteed to 429: short-circuit before
+    // spending a ticket on the distributed ratelimiter when the local cache
+    // (or the global lock) already knows this bucket is exhausted.
+    if let Some(retry_after) = local_limits.retry_after(&app_id, &key).await {
+        if retry_after <= MAX_LOCAL_SLEEP {
+            tokio::time::sleep(retry_after).await;
+        } else {
+            return Ok(Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header("Retry-After", retry_after.as_secs_f64().ceil().to_string())
+                .body(Body::empty())?);
+        }
+    }
+
+    let start_ticket_request = Instant::now();
+    if let Err(e) = ratelimiter.ticket(key.clone()).await {
+        error!("Failed to receive ticket for ratelimiting: {:?}", e);
+        bail!("failed to reteive ticket");
+    }
     let time_took_ticket = Instant::now() - start_ticket_request;
 
-    request.headers_mut().insert(
-        AUTHORIZATION,
-        HeaderValue::from_bytes(token.as_bytes())
-            .expect("strings are guaranteed to be valid utf-8"),
-    );
     request
         .headers_mut()
         .insert(HOST, HeaderValue::from_static("discord.com"));
@@ -107,10 +157,9 @@ pub async fn handle_request(
     request.headers_mut().remove(TRANSFER_ENCODING);
     request.headers_mut().remove(UPGRADE);
     request.headers_mut().remove(AUTHORIZATION);
-    request.headers_mut().append(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bot {}", token))?,
-    );
+    // Proxy-internal routing header; Discord has no use for it.
+    request.headers_mut().remove(APPLICATION_ID_HEADER);
+    request.headers_mut().append(AUTHORIZATION, auth_header);
 
     let uri = match Uri::from_str(&uri_string) {
         Ok(uri) => uri,
@@ -139,16 +188,79 @@ pub async fn handle_request(
         "X-Upstream-Ms",
         HeaderValue::from_str(&upstream_time_took.as_millis().to_string()).unwrap(),
     );
-    
+
+    // Discord groups several routes under one shared bucket; learn the
+    // mapping so future requests for this path route by bucket too. This is
+    // also the key this very response's accounting below must land under:
+    // once the mapping is learned, every later request on this path resolves
+    // straight to the bucket key and would never consult an entry left under
+    // the path hash.
+    if let Some(bucket) = resp.headers().get("X-RateLimit-Bucket") {
+        if let Ok(bucket) = bucket.to_str() {
+            // Discord's bucket hash is derived from the route, not the
+            // token, so two applications hitting the same endpoint can
+            // learn the same bare bucket id; prefix it with the app id to
+            // keep the isolation `hash` already established above.
+            let bucket_key = if app_id.is_empty() {
+                bucket.to_string()
+            } else {
+                format!("{}:{}", app_id, bucket)
+            };
+            ratelimiter.record_bucket(hash, bucket_key.clone()).await;
+            key = bucket_key;
+        }
+    }
+
+    local_limits.record(&app_id, &key, resp.headers()).await;
+
     let ratelimit_headers = resp
         .headers()
         .into_iter()
         .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
         .collect();
 
-    if header_sender.send(ratelimit_headers).is_err() {
-        error!("Error when sending ratelimit headers to ratelimiter");
+    if let Err(e) = ratelimiter.submit_headers(key, ratelimit_headers).await {
+        error!("Error when sending ratelimit headers to ratelimiter: {:?}", e);
     };
 
+    // Negotiate compression toward our own clients; skip anything Discord
+    // already compressed, anything guaranteed bodyless, and anything too
+    // small to be worth it.
+    let bodyless = is_head
+        || matches!(resp.status(), StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED);
+
+    if !bodyless && !resp.headers().contains_key(CONTENT_ENCODING) {
+        let config = ratelimiter.config();
+        let body_len = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+        // No Content-Length means a chunked body of unknown size (common for
+        // Discord's larger list endpoints); treat it as large enough rather
+        // than skip compression on exactly the responses most worth
+        // compressing. `compression_min_size` only exists to skip the
+        // overhead of compressing bodies we know are tiny.
+        let large_enough = body_len.map_or(true, |len| len >= config.compression_min_size);
+
+        if large_enough {
+            if let Some(encoding) = accept_encoding
+                .as_ref()
+                .and_then(|header| negotiate(header, &config.compression_algorithms))
+            {
+                let (mut parts, body) = resp.into_parts();
+                parts.headers.remove(CONTENT_LENGTH);
+                parts.headers.insert(
+                    CONTENT_ENCODING,
+                    HeaderValue::from_static(encoding.as_str()),
+                );
+                parts
+                    .headers
+                    .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+                resp = Response::from_parts(parts, compress(body, encoding));
+            }
+        }
+    }
+
     Ok(resp)
 }
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http::HeaderMap;
+use tokio::sync::RwLock;
+
+/// Longest a stale entry is kept past its own expiry before being swept.
+/// Entries are only cleaned up lazily (on the next `record`), so this is a
+/// grace period against unbounded growth, not a precise TTL.
+const STALE_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+struct BucketLimit {
+    remaining: u64,
+    reset_at: Instant,
+}
+
+/// Local, best-effort cache of Discord's per-bucket rate limit state, kept
+/// warm from response headers so the proxy can short-circuit requests that
+/// are guaranteed to come back as a 429 instead of spending a round trip.
+#[derive(Debug, Clone, Default)]
+pub struct LocalLimitCache {
+    buckets: Arc<RwLock<HashMap<String, BucketLimit>>>,
+    /// Global lock expiry, keyed by application id: Discord's global rate
+    /// limit is scoped to the token that hit it, so one app's global 429
+    /// must not stall the others sharing this cache.
+    global_until: Arc<RwLock<HashMap<String, Instant>>>,
+}
+
+impl LocalLimitCache {
+    /// Returns how long the caller should wait before this request would be
+    /// allowed, if the global lock or the bucket's local cache already
+    /// guarantees a 429.
+    pub async fn retry_after(&self, app_id: &str, key: &str) -> Option<Duration> {
+        let now = Instant::now();
+
+        if let Some(until) = self.global_until.read().await.get(app_id).copied() {
+            if now < until {
+                return Some(until - now);
+            }
+        }
+
+        let buckets = self.buckets.read().await;
+        let bucket = buckets.get(key)?;
+        (bucket.remaining == 0 && now < bucket.reset_at).then(|| bucket.reset_at - now)
+    }
+
+    /// Updates the local cache from a response's rate limit headers, and
+    /// sweeps entries that expired more than `STALE_GRACE` ago so the cache
+    /// doesn't grow for the full cardinality of buckets/apps ever seen.
+    pub async fn record(&self, app_id: &str, key: &str, headers: &HeaderMap) {
+        let now = Instant::now();
+
+        if headers
+            .get("X-RateLimit-Global")
+            .and_then(|v| v.to_str().ok())
+            == Some("true")
+        {
+            if let Some(retry_after) = header_f64(headers, "Retry-After") {
+                let mut global_until = self.global_until.write().await;
+                global_until.retain(|_, until| now < *until + STALE_GRACE);
+                global_until.insert(
+                    app_id.to_string(),
+                    now + Duration::from_secs_f64(retry_after),
+                );
+            }
+            return;
+        }
+
+        let remaining = headers
+            .get("X-RateLimit-Remaining")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        let reset_after = header_f64(headers, "X-RateLimit-Reset-After");
+
+        if let (Some(remaining), Some(reset_after)) = (remaining, reset_after) {
+            let mut buckets = self.buckets.write().await;
+            buckets.retain(|_, bucket| now < bucket.reset_at + STALE_GRACE);
+            buckets.insert(
+                key.to_string(),
+                BucketLimit {
+                    remaining,
+                    reset_at: now + Duration::from_secs_f64(reset_after),
+                },
+            );
+        }
+    }
+}
+
+fn header_f64(headers: &HeaderMap, name: &str) -> Option<f64> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<f64>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn global_headers(retry_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-RateLimit-Global", HeaderValue::from_static("true"));
+        headers.insert("Retry-After", HeaderValue::from_str(retry_after).unwrap());
+        headers
+    }
+
+    fn bucket_headers(remaining: &str, reset_after: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-RateLimit-Remaining",
+            HeaderValue::from_str(remaining).unwrap(),
+        );
+        headers.insert(
+            "X-RateLimit-Reset-After",
+            HeaderValue::from_str(reset_after).unwrap(),
+        );
+        headers
+    }
+
+    #[tokio::test]
+    async fn unknown_key_does_not_block() {
+        let cache = LocalLimitCache::default();
+        assert!(cache.retry_after("app1", "key1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn exhausted_bucket_blocks_until_reset() {
+        let cache = LocalLimitCache::default();
+        cache.record("app1", "key1", &bucket_headers("0", "30")).await;
+        assert!(cache.retry_after("app1", "key1").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn remaining_above_zero_does_not_block() {
+        let cache = LocalLimitCache::default();
+        cache.record("app1", "key1", &bucket_headers("1", "30")).await;
+        assert!(cache.retry_after("app1", "key1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_reset_no_longer_blocks() {
+        let cache = LocalLimitCache::default();
+        cache.record("app1", "key1", &bucket_headers("0", "0")).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert!(cache.retry_after("app1", "key1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn global_lock_takes_precedence_over_a_fresh_bucket() {
+        let cache = LocalLimitCache::default();
+        cache.record("app1", "key1", &bucket_headers("5", "30")).await;
+        cache.record("app1", "key1", &global_headers("30")).await;
+        assert!(cache.retry_after("app1", "key1").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn global_lock_does_not_leak_across_applications() {
+        let cache = LocalLimitCache::default();
+        cache.record("app1", "key1", &global_headers("30")).await;
+        assert!(cache.retry_after("app1", "key1").await.is_some());
+        assert!(cache.retry_after("app2", "key1").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn recording_a_bucket_sweeps_entries_stale_past_the_grace_period() {
+        let cache = LocalLimitCache::default();
+        cache.buckets.write().await.insert(
+            "stale".to_string(),
+            BucketLimit {
+                remaining: 0,
+                reset_at: Instant::now() - STALE_GRACE - Duration::from_secs(1),
+            },
+        );
+
+        cache.record("app1", "fresh", &bucket_headers("1", "30")).await;
+
+        assert!(!cache.buckets.read().await.contains_key("stale"));
+        assert!(cache.buckets.read().await.contains_key("fresh"));
+    }
+}
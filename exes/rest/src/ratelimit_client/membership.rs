@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+/// Consecutive failed probes before a `Suspect` node is considered `Down`
+/// and removed from the ring.
+const FAILURE_THRESHOLD: u32 = 3;
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeState {
+    Up,
+    Suspect,
+    Down,
+}
+
+/// Tracks the health of a single ratelimiter node between probes.
+#[derive(Debug, Clone)]
+pub struct Health {
+    pub state: NodeState,
+    consecutive_failures: u32,
+    next_probe_at: Instant,
+}
+
+impl Health {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            state: NodeState::Up,
+            consecutive_failures: 0,
+            next_probe_at: now,
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_probe_at
+    }
+
+    /// Records a successful probe. Returns `true` if the node just
+    /// transitioned back to `Up` and should be re-added to the ring.
+    pub fn record_success(&mut self, now: Instant) -> bool {
+        let recovered = self.state != NodeState::Up;
+        self.state = NodeState::Up;
+        self.consecutive_failures = 0;
+        self.next_probe_at = now + BASE_BACKOFF;
+        recovered
+    }
+
+    /// Records a failed probe. Returns `true` if the node just
+    /// transitioned to `Down` and should be removed from the ring.
+    pub fn record_failure(&mut self, now: Instant) -> bool {
+        let was_down = self.state == NodeState::Down;
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        self.state = if self.consecutive_failures >= FAILURE_THRESHOLD {
+            NodeState::Down
+        } else {
+            NodeState::Suspect
+        };
+
+        let backoff = BASE_BACKOFF.saturating_mul(1 << self.consecutive_failures.min(5));
+        self.next_probe_at = now + backoff.min(MAX_BACKOFF);
+
+        self.state == NodeState::Down && !was_down
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_up_and_due_immediately() {
+        let now = Instant::now();
+        let health = Health::new(now);
+        assert_eq!(health.state, NodeState::Up);
+        assert!(health.is_due(now));
+    }
+
+    #[test]
+    fn repeated_success_reports_no_transition() {
+        let now = Instant::now();
+        let mut health = Health::new(now);
+        assert!(!health.record_success(now));
+        assert_eq!(health.state, NodeState::Up);
+    }
+
+    #[test]
+    fn failures_below_threshold_are_only_suspect() {
+        let now = Instant::now();
+        let mut health = Health::new(now);
+
+        assert!(!health.record_failure(now));
+        assert_eq!(health.state, NodeState::Suspect);
+        assert!(!health.record_failure(now));
+        assert_eq!(health.state, NodeState::Suspect);
+    }
+
+    #[test]
+    fn reaching_the_threshold_transitions_to_down_once() {
+        let now = Instant::now();
+        let mut health = Health::new(now);
+
+        health.record_failure(now);
+        health.record_failure(now);
+        assert!(
+            health.record_failure(now),
+            "the failure that reaches the threshold should report the down transition"
+        );
+        assert_eq!(health.state, NodeState::Down);
+    }
+
+    #[test]
+    fn recovery_resets_the_failure_count_and_reports_the_transition() {
+        let now = Instant::now();
+        let mut health = Health::new(now);
+        health.record_failure(now);
+        health.record_failure(now);
+
+        assert!(health.record_success(now));
+        assert_eq!(health.state, NodeState::Up);
+
+        // Failure count was reset, so a single subsequent failure is only
+        // suspect, not immediately down again.
+        assert!(!health.record_failure(now));
+        assert_eq!(health.state, NodeState::Suspect);
+    }
+
+    #[test]
+    fn a_failure_backs_off_the_next_probe() {
+        let now = Instant::now();
+        let mut health = Health::new(now);
+        health.record_failure(now);
+
+        assert!(!health.is_due(now), "probe should be backed off, not immediate");
+        assert!(health.is_due(now + MAX_BACKOFF));
+    }
+}
@@ -1,25 +1,49 @@
 use crate::config::ReverseProxyConfig;
 
+use self::membership::{Health, NodeState};
 use self::remote_hashring::{HashRingWrapper, MetadataMap, VNode};
 use anyhow::anyhow;
+use futures::stream::{FuturesUnordered, StreamExt};
 use opentelemetry::global;
 use proto::nova::ratelimit::ratelimiter::{BucketSubmitTicketRequest, HeadersSubmitRequest};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::{broadcast, RwLock};
 use tonic::Request;
-use tracing::{debug, error, info_span, instrument, trace_span, Instrument, Span};
+use tracing::{debug, error, info, info_span, instrument, trace_span, Instrument, Span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
+mod membership;
 mod remote_hashring;
 
+/// How often membership is reconciled: DNS is diffed and every known node
+/// due for a probe is pinged.
+const MEMBERSHIP_TICK: Duration = Duration::from_secs(2);
+
+/// How long a learned path-hash -> bucket mapping is kept before it's
+/// treated as stale and evicted. Without this the map grows for the full
+/// cardinality of routes ever seen; re-learning a mapping after eviction
+/// just costs the one-request warmup every still-active route already pays
+/// the first time it's seen.
+const BUCKET_TTL: Duration = Duration::from_secs(3600);
+
 #[derive(Clone, Debug)]
 pub struct RemoteRatelimiter {
     remotes: Arc<RwLock<HashRingWrapper>>,
+    /// Every node discovered via DNS, including ones currently suspected or
+    /// down, so they keep being probed and can rejoin the ring on recovery.
+    nodes: Arc<RwLock<HashMap<String, VNode>>>,
+    health: Arc<RwLock<HashMap<String, Health>>>,
+    /// Maps a path hash to the authoritative `X-RateLimit-Bucket` id Discord
+    /// reported for it (and when it was learned), so routes that share a
+    /// bucket land on the same node. Entries older than `BUCKET_TTL` are
+    /// swept so this doesn't grow for the full cardinality of routes ever
+    /// seen over the proxy's lifetime.
+    buckets: Arc<RwLock<HashMap<String, (String, Instant)>>>,
     stop: Arc<tokio::sync::broadcast::Sender<()>>,
     config: ReverseProxyConfig,
 }
@@ -35,53 +59,156 @@ impl Drop for RemoteRatelimiter {
 }
 
 impl RemoteRatelimiter {
-    async fn get_ratelimiters(&self) -> Result<(), anyhow::Error> {
-        // get list of dns responses
-        let responses = dns_lookup::lookup_host(&self.config.ratelimiter_address)?
+    /// Diffs the current DNS answer against known nodes: newly seen
+    /// addresses join the ring, and addresses whose A-record disappeared
+    /// are dropped immediately rather than waiting to be probed as down.
+    async fn sync_membership(&self) -> Result<(), anyhow::Error> {
+        let addresses: HashSet<String> = dns_lookup::lookup_host(&self.config.ratelimiter_address)?
             .into_iter()
             .filter(|address| address.is_ipv4())
-            .map(|address| address.to_string());
+            .map(|address| address.to_string())
+            .collect();
+
+        let new_addresses: Vec<String> = {
+            let nodes = self.nodes.read().await;
+            addresses
+                .iter()
+                .filter(|address| !nodes.contains_key(address.as_str()))
+                .cloned()
+                .collect()
+        };
+
+        // Connect to newly discovered nodes before taking any lock, so a
+        // slow-to-connect or unreachable address cannot stall the
+        // read-locked routing lookups every in-flight ticket/headers call
+        // depends on.
+        let mut connected = Vec::with_capacity(new_addresses.len());
+        for address in new_addresses {
+            // An address that fails to connect is skipped (and retried on the
+            // next tick) so it cannot hold back removal of stale nodes.
+            match VNode::new(address.clone(), self.config.ratelimiter_port).await {
+                Ok(node) => connected.push((address, node)),
+                Err(err) => error!("failed to connect to ratelimiter node {}: {}", address, err),
+            }
+        }
 
-        let mut write = self.remotes.write().await;
+        let mut nodes = self.nodes.write().await;
+        let mut health = self.health.write().await;
+        let mut ring = self.remotes.write().await;
 
-        for ip in responses {
-            let a = VNode::new(ip, self.config.ratelimiter_port).await?;
-            write.add(a.clone());
+        let stale: Vec<String> = nodes
+            .keys()
+            .filter(|id| !addresses.contains(id.as_str()))
+            .cloned()
+            .collect();
+        for id in stale {
+            if let Some(node) = nodes.remove(&id) {
+                info!("ratelimiter node {} dropped from dns, removing", id);
+                ring.remove(&node);
+            }
+            health.remove(&id);
+        }
+
+        for (address, node) in connected {
+            if nodes.contains_key(&address) {
+                continue;
+            }
+
+            ring.add(node.clone());
+            nodes.insert(address.clone(), node);
+            health.insert(address, Health::new(Instant::now()));
         }
 
         Ok(())
     }
 
+    /// Pings every known node that is due for a probe and applies state
+    /// transitions (Up/Suspect/Down) to the ring.
+    async fn probe_health(&self) {
+        let now = Instant::now();
+
+        let due: Vec<(String, VNode)> = {
+            let nodes = self.nodes.read().await;
+            let health = self.health.read().await;
+            nodes
+                .iter()
+                .filter(|(id, _)| health.get(*id).map_or(true, |h| h.is_due(now)))
+                .map(|(id, node)| (id.clone(), node.clone()))
+                .collect()
+        };
+
+        let results: Vec<(String, bool)> = due
+            .into_iter()
+            .map(|(id, mut node)| async move { (id, node.ping().await.is_ok()) })
+            .collect::<FuturesUnordered<_>>()
+            .collect()
+            .await;
+
+        // Lock order must match `sync_membership`'s (nodes, then health, then
+        // ring): both run sequentially from the same background task today,
+        // so a mismatched order has never deadlocked, but that's an
+        // invariant of the caller, not of this function. Keep the order
+        // pinned so a future parallelization of the two doesn't introduce one.
+        let nodes = self.nodes.read().await;
+        let mut health = self.health.write().await;
+        let mut ring = self.remotes.write().await;
+
+        for (id, healthy) in results {
+            let entry = health
+                .entry(id.clone())
+                .or_insert_with(|| Health::new(now));
+            let previous_state = entry.state;
+
+            if healthy {
+                if entry.record_success(now) {
+                    if let Some(node) = nodes.get(&id) {
+                        info!("ratelimiter node {} recovered, re-adding to ring", id);
+                        ring.add(node.clone());
+                    }
+                }
+            } else {
+                let went_down = entry.record_failure(now);
+                if went_down && previous_state != NodeState::Down {
+                    if let Some(node) = nodes.get(&id) {
+                        error!("ratelimiter node {} marked down, removing from ring", id);
+                        ring.remove(node);
+                    }
+                } else if entry.state == NodeState::Suspect && previous_state == NodeState::Up {
+                    debug!("ratelimiter node {} is suspect", id);
+                }
+            }
+        }
+    }
+
     #[must_use]
     pub fn new(config: ReverseProxyConfig) -> Self {
         let (rx, mut tx) = broadcast::channel(1);
         let obj = Self {
             remotes: Arc::new(RwLock::new(HashRingWrapper::default())),
+            nodes: Arc::new(RwLock::new(HashMap::new())),
+            health: Arc::new(RwLock::new(HashMap::new())),
+            buckets: Arc::new(RwLock::new(HashMap::new())),
             stop: Arc::new(rx),
             config,
         };
 
         let obj_clone = obj.clone();
-        // Task to update the ratelimiters in the background
+        // Task keeping the ring a live view of cluster health: diff DNS,
+        // then probe every node due for a check.
         tokio::spawn(async move {
             loop {
-                debug!("refreshing");
+                debug!("reconciling membership");
 
-                match obj_clone.get_ratelimiters().await {
-                    Ok(_) => {
-                        debug!("refreshed ratelimiting servers")
-                    }
-                    Err(err) => {
-                        error!("refreshing ratelimiting servers failed {}", err);
-                    }
+                if let Err(err) = obj_clone.sync_membership().await {
+                    error!("refreshing ratelimiting servers failed {}", err);
                 }
 
-                let sleep = tokio::time::sleep(Duration::from_secs(10));
+                obj_clone.probe_health().await;
+
+                let sleep = tokio::time::sleep(MEMBERSHIP_TICK);
                 tokio::pin!(sleep);
                 tokio::select! {
-                    () = &mut sleep => {
-                        debug!("timer elapsed");
-                    },
+                    () = &mut sleep => {},
                     _ = tx.recv() => {}
                 }
             }
@@ -90,41 +217,103 @@ impl RemoteRatelimiter {
         obj
     }
 
+    pub fn config(&self) -> &ReverseProxyConfig {
+        &self.config
+    }
+
+    /// Returns the routing key for a path hash: the bucket id Discord
+    /// reported for it if one is already known and not yet stale, otherwise
+    /// the path hash itself, so still-unseen (or evicted) paths route on
+    /// their own hash.
+    pub async fn resolve_key(&self, path_hash: &str) -> String {
+        self.buckets
+            .read()
+            .await
+            .get(path_hash)
+            .filter(|(_, learned_at)| learned_at.elapsed() < BUCKET_TTL)
+            .map(|(bucket_id, _)| bucket_id.clone())
+            .unwrap_or_else(|| path_hash.to_string())
+    }
+
+    /// Records the bucket id Discord reported for a path hash, so later
+    /// requests on the same path route by bucket instead of by path, and
+    /// sweeps any mapping stale enough to have exceeded `BUCKET_TTL`.
+    pub async fn record_bucket(&self, path_hash: String, bucket_id: String) {
+        let mut buckets = self.buckets.write().await;
+        buckets.retain(|_, (_, learned_at)| learned_at.elapsed() < BUCKET_TTL);
+        buckets.insert(path_hash, (bucket_id, Instant::now()));
+    }
+
+    /// Returns the primary node for `path` plus its configured replicas,
+    /// in ring order, so callers can fail over when the primary is down.
+    async fn replicas_for(&self, path: &str) -> anyhow::Result<Vec<VNode>> {
+        let candidates = self
+            .remotes
+            .read()
+            .instrument(trace_span!("acquiring ring lock"))
+            .await
+            .get_replicas(path, self.config.replication_factor);
+
+        if candidates.is_empty() {
+            return Err(anyhow!(
+                "did not compute ratelimit because no ratelimiter nodes are detected"
+            ));
+        }
+
+        Ok(candidates)
+    }
+
     #[instrument(name = "ticket task")]
     pub fn ticket(
         &self,
         path: String,
     ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'static>> {
-        let remotes = self.remotes.clone();
+        let this = self.clone();
         Box::pin(
             async move {
-                // Getting the node managing this path
-                let mut node = remotes
-                    .write()
-                    .instrument(trace_span!("acquiring ring lock"))
-                    .await
-                    .get(&path)
-                    .and_then(|node| Some(node.clone()))
-                    .ok_or_else(|| {
-                        anyhow!(
-                            "did not compute ratelimit because no ratelimiter nodes are detected"
-                        )
-                    })?;
+                // Getting the primary plus its replicas, so a dead primary
+                // does not stall the ticket.
+                let candidates = this.replicas_for(&path).await?;
 
                 // Initialize span for tracing (headers injection)
                 let span = info_span!("remote request");
                 let context = span.context();
-                let mut request = Request::new(BucketSubmitTicketRequest { path });
-                global::get_text_map_propagator(|propagator| {
-                    propagator.inject_context(&context, &mut MetadataMap(request.metadata_mut()))
-                });
 
-                // Requesting
-                node.submit_ticket(request)
-                    .instrument(info_span!("waiting for ticket response"))
-                    .await?;
+                let mut last_err = None;
+                for mut node in candidates {
+                    let mut request = Request::new(BucketSubmitTicketRequest {
+                        path: path.clone(),
+                    });
+                    global::get_text_map_propagator(|propagator| {
+                        propagator
+                            .inject_context(&context, &mut MetadataMap(request.metadata_mut()))
+                    });
 
-                Ok(())
+                    match node
+                        .submit_ticket(request)
+                        .instrument(info_span!("waiting for ticket response"))
+                        .await
+                    {
+                        Ok(_) => return Ok(()),
+                        Err(err) => {
+                            // A transport error here doesn't tell us whether
+                            // the node granted the ticket before the response
+                            // was lost; failing over to the next replica
+                            // risks spending a second ticket for one logical
+                            // request. We accept that over-count rather than
+                            // leave the request stuck on a replica that may
+                            // really be down — an intentional
+                            // at-most-once-becomes-at-least-once tradeoff,
+                            // not an oversight.
+                            debug!("replica {:?} unreachable, trying next: {}", node, err);
+                            last_err = Some(err);
+                        }
+                    }
+                }
+
+                Err(last_err
+                    .map(anyhow::Error::from)
+                    .unwrap_or_else(|| anyhow!("all ratelimiter replicas were unreachable")))
             }
             .instrument(Span::current()),
         )
@@ -135,35 +324,119 @@ impl RemoteRatelimiter {
         path: String,
         headers: HashMap<String, String>,
     ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'static>> {
-        let remotes = self.remotes.clone();
+        let this = self.clone();
         Box::pin(async move {
-            let mut node = remotes
-                .write()
-                .instrument(trace_span!("acquiring ring lock"))
-                .await
-                .get(&path)
-                .and_then(|node| Some(node.clone()))
-                .ok_or_else(|| {
-                    anyhow!("did not compute ratelimit because no ratelimiter nodes are detected")
-                })?;
+            // Fan the headers out to every replica so bucket state survives
+            // the loss of any single node.
+            let candidates = this.replicas_for(&path).await?;
 
             let span = info_span!("remote request");
             let context = span.context();
             let time = SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)?
                 .as_millis();
-            let mut request = Request::new(HeadersSubmitRequest {
-                path,
-                precise_time: time as u64,
-                headers,
-            });
-            global::get_text_map_propagator(|propagator| {
-                propagator.inject_context(&context, &mut MetadataMap(request.metadata_mut()))
-            });
-
-            node.submit_headers(request).await?;
-
-            Ok(())
+
+            let mut futures = FuturesUnordered::new();
+            for mut node in candidates {
+                let mut request = Request::new(HeadersSubmitRequest {
+                    path: path.clone(),
+                    precise_time: time as u64,
+                    headers: headers.clone(),
+                });
+                global::get_text_map_propagator(|propagator| {
+                    propagator.inject_context(&context, &mut MetadataMap(request.metadata_mut()))
+                });
+                futures.push(async move { node.submit_headers(request).await });
+            }
+
+            let mut succeeded = false;
+            while let Some(result) = futures.next().await {
+                match result {
+                    Ok(_) => succeeded = true,
+                    Err(err) => debug!("replica failed to receive headers: {}", err),
+                }
+            }
+
+            if succeeded {
+                Ok(())
+            } else {
+                Err(anyhow!("all ratelimiter replicas rejected header submission"))
+            }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_ratelimiter() -> RemoteRatelimiter {
+        let (tx, _rx) = broadcast::channel(1);
+        RemoteRatelimiter {
+            remotes: Arc::new(RwLock::new(HashRingWrapper::default())),
+            nodes: Arc::new(RwLock::new(HashMap::new())),
+            health: Arc::new(RwLock::new(HashMap::new())),
+            buckets: Arc::new(RwLock::new(HashMap::new())),
+            stop: Arc::new(tx),
+            config: ReverseProxyConfig {
+                ratelimiter_address: "localhost".to_string(),
+                ratelimiter_port: 0,
+                replication_factor: 0,
+                compression_algorithms: vec![],
+                compression_min_size: 0,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn resolve_key_falls_back_to_the_path_hash_when_no_bucket_is_known() {
+        let ratelimiter = test_ratelimiter();
+        assert_eq!(ratelimiter.resolve_key("hash1").await, "hash1");
+    }
+
+    #[tokio::test]
+    async fn resolve_key_uses_the_learned_bucket_once_recorded() {
+        let ratelimiter = test_ratelimiter();
+        ratelimiter
+            .record_bucket("hash1".to_string(), "bucketA".to_string())
+            .await;
+        assert_eq!(ratelimiter.resolve_key("hash1").await, "bucketA");
+    }
+
+    #[tokio::test]
+    async fn unrelated_path_hashes_keep_resolving_independently() {
+        let ratelimiter = test_ratelimiter();
+        ratelimiter
+            .record_bucket("hash1".to_string(), "bucketA".to_string())
+            .await;
+        assert_eq!(ratelimiter.resolve_key("hash2").await, "hash2");
+    }
+
+    #[tokio::test]
+    async fn stale_bucket_mapping_falls_back_to_the_path_hash() {
+        let ratelimiter = test_ratelimiter();
+        let stale_since = Instant::now() - BUCKET_TTL - Duration::from_secs(1);
+        ratelimiter
+            .buckets
+            .write()
+            .await
+            .insert("hash1".to_string(), ("bucketA".to_string(), stale_since));
+        assert_eq!(ratelimiter.resolve_key("hash1").await, "hash1");
+    }
+
+    #[tokio::test]
+    async fn recording_a_bucket_sweeps_other_stale_mappings() {
+        let ratelimiter = test_ratelimiter();
+        let stale_since = Instant::now() - BUCKET_TTL - Duration::from_secs(1);
+        ratelimiter
+            .buckets
+            .write()
+            .await
+            .insert("stale".to_string(), ("bucketOld".to_string(), stale_since));
+        ratelimiter
+            .record_bucket("fresh".to_string(), "bucketB".to_string())
+            .await;
+        assert_eq!(ratelimiter.resolve_key("stale").await, "stale");
+        assert_eq!(ratelimiter.resolve_key("fresh").await, "bucketB");
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use anyhow::Result;
+use opentelemetry::propagation::Injector;
+use proto::nova::ratelimit::ratelimiter::ratelimiter_client::RatelimiterClient;
+use proto::nova::ratelimit::ratelimiter::{
+    BucketSubmitTicketRequest, BucketSubmitTicketResponse, HeadersSubmitRequest,
+    HeadersSubmitResponse,
+};
+use tonic::metadata::{MetadataKey, MetadataMap as TonicMetadataMap};
+use tonic::transport::Channel;
+use tonic::{Request, Response, Status};
+
+/// Number of points each physical node occupies on the ring, smoothing out
+/// the distribution of paths across nodes.
+const VIRTUAL_NODES_PER_HOST: usize = 128;
+
+/// Longest a probe waits for a reply before the node is treated as
+/// unreachable. A frozen/half-open node (TCP still up, app wedged) would
+/// otherwise hang the calling RPC for the OS TCP timeout instead of
+/// reading as down quickly.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest a single replica gets to answer a ticket or headers submission
+/// before the caller treats it as unreachable and moves on to the next
+/// replica. Without this, a hung (not merely down) primary would stall
+/// every request routed to it instead of failing over — the exact problem
+/// replica failover exists to prevent.
+const RPC_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Reserved path used to probe liveness via the existing ticket RPC, rather
+/// than standing up a dedicated health-check endpoint.
+///
+/// The ratelimiter server lives outside this crate, so this client cannot
+/// guarantee it treats the path as a no-op; today it just reserves a bucket
+/// like any other path would. That bucket is harmless (nothing ever looks
+/// it up for real traffic), but it does mean every `MEMBERSHIP_TICK` spends
+/// a real ticket. Confirm the server-side no-op before relying on this for
+/// anything beyond liveness. `ping` is bounded by `PROBE_TIMEOUT` so that if
+/// this path were ever itself rate-limited server-side, a hung response
+/// reads as a failed probe rather than wedging the membership loop.
+const HEALTH_CHECK_PATH: &str = "__nova_health_check__";
+
+/// Runs `fut`, turning a `timeout` elapse into a `DeadlineExceeded` status
+/// so callers can treat "hung" the same as any other RPC failure.
+async fn with_timeout<T>(
+    timeout: Duration,
+    fut: impl Future<Output = Result<T, Status>>,
+) -> Result<T, Status> {
+    tokio::time::timeout(timeout, fut).await.unwrap_or_else(|_| {
+        Err(Status::deadline_exceeded(
+            "ratelimiter node did not respond in time",
+        ))
+    })
+}
+
+#[derive(Clone)]
+pub struct VNode {
+    address: String,
+    port: u16,
+    client: RatelimiterClient<Channel>,
+}
+
+impl std::fmt::Debug for VNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VNode")
+            .field("address", &self.address)
+            .field("port", &self.port)
+            .finish()
+    }
+}
+
+impl VNode {
+    pub async fn new(address: String, port: u16) -> Result<Self> {
+        let client = RatelimiterClient::connect(format!("http://{}:{}", address, port)).await?;
+        Ok(Self {
+            address,
+            port,
+            client,
+        })
+    }
+
+    /// Identifier used to dedupe virtual nodes belonging to the same physical node.
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Lightweight liveness probe, reusing the ticket RPC with a reserved
+    /// path rather than requiring a dedicated health-check endpoint.
+    pub async fn ping(&mut self) -> Result<(), Status> {
+        with_timeout(
+            PROBE_TIMEOUT,
+            self.client
+                .submit_ticket(Request::new(BucketSubmitTicketRequest {
+                    path: HEALTH_CHECK_PATH.to_string(),
+                })),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn submit_ticket(
+        &mut self,
+        request: Request<BucketSubmitTicketRequest>,
+    ) -> Result<Response<BucketSubmitTicketResponse>, Status> {
+        with_timeout(RPC_TIMEOUT, self.client.submit_ticket(request)).await
+    }
+
+    pub async fn submit_headers(
+        &mut self,
+        request: Request<HeadersSubmitRequest>,
+    ) -> Result<Response<HeadersSubmitResponse>, Status> {
+        with_timeout(RPC_TIMEOUT, self.client.submit_headers(request)).await
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct HashRingWrapper {
+    ring: BTreeMap<u64, VNode>,
+}
+
+impl HashRingWrapper {
+    fn hash_key(key: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn add(&mut self, node: VNode) {
+        for vnode in 0..VIRTUAL_NODES_PER_HOST {
+            let hash = Self::hash_key(&format!("{}#{}", node.id(), vnode));
+            self.ring.insert(hash, node.clone());
+        }
+    }
+
+    /// Removes every virtual node belonging to the given physical node.
+    pub fn remove(&mut self, node: &VNode) {
+        self.ring.retain(|_, existing| existing.id() != node.id());
+    }
+
+    /// Returns the node owning `path` on the ring.
+    pub fn get(&self, path: &str) -> Option<&VNode> {
+        if self.ring.is_empty() {
+            return None;
+        }
+
+        let hash = Self::hash_key(path);
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node)
+    }
+
+    /// Returns the primary node for `path` plus the next `n` distinct nodes
+    /// walking clockwise around the ring, so callers can fail over to a
+    /// replica when the primary is unreachable.
+    pub fn get_replicas(&self, path: &str, n: usize) -> Vec<VNode> {
+        if self.ring.is_empty() {
+            return Vec::new();
+        }
+
+        let hash = Self::hash_key(path);
+        let mut seen = HashSet::new();
+        let mut replicas = Vec::with_capacity(n + 1);
+
+        for (_, node) in self.ring.range(hash..).chain(self.ring.iter()) {
+            if seen.insert(node.id()) {
+                replicas.push(node.clone());
+                if replicas.len() == n + 1 {
+                    break;
+                }
+            }
+        }
+
+        replicas
+    }
+}
+
+pub struct MetadataMap<'a>(pub &'a mut TonicMetadataMap);
+
+impl<'a> Injector for MetadataMap<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        if let Ok(key) = MetadataKey::from_bytes(key.as_bytes()) {
+            if let Ok(value) = value.parse() {
+                self.0.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl VNode {
+    /// Builds a `VNode` backed by a lazily-connecting channel, so ring tests
+    /// can exercise routing logic without a live ratelimiter to dial.
+    fn for_test(id: &str) -> Self {
+        let channel = Channel::from_shared(format!("http://{}", id))
+            .expect("valid uri")
+            .connect_lazy();
+        Self {
+            address: id.to_string(),
+            port: 0,
+            client: RatelimiterClient::new(channel),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str) -> VNode {
+        VNode::for_test(id)
+    }
+
+    #[test]
+    fn get_on_empty_ring_returns_none() {
+        let ring = HashRingWrapper::default();
+        assert!(ring.get("some/path").is_none());
+    }
+
+    #[test]
+    fn get_replicas_on_empty_ring_returns_nothing() {
+        let ring = HashRingWrapper::default();
+        assert!(ring.get_replicas("some/path", 2).is_empty());
+    }
+
+    #[test]
+    fn get_replicas_returns_distinct_physical_nodes() {
+        let mut ring = HashRingWrapper::default();
+        for id in ["a:1", "b:2", "c:3"] {
+            ring.add(node(id));
+        }
+
+        let replicas = ring.get_replicas("some/path", 2);
+        assert_eq!(replicas.len(), 3);
+
+        let ids: HashSet<String> = replicas.iter().map(VNode::id).collect();
+        assert_eq!(ids.len(), 3, "replicas must be distinct physical nodes");
+    }
+
+    #[test]
+    fn get_replicas_wraps_around_the_ring() {
+        let mut ring = HashRingWrapper::default();
+        ring.add(node("only:1"));
+
+        // Asking for more replicas than physical nodes exist walks the whole
+        // ring, wrapping past its end, and returns each physical node once.
+        let replicas = ring.get_replicas("some/path", 5);
+        assert_eq!(replicas.len(), 1);
+    }
+
+    #[test]
+    fn remove_drops_every_virtual_node_for_the_physical_node() {
+        let mut ring = HashRingWrapper::default();
+        let a = node("a:1");
+        ring.add(a.clone());
+        ring.add(node("b:2"));
+
+        ring.remove(&a);
+
+        for path in ["path/one", "path/two", "path/three", "path/four"] {
+            assert_ne!(ring.get(path).map(VNode::id), Some(a.id()));
+        }
+    }
+}